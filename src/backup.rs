@@ -0,0 +1,101 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+
+use crate::fs::Fs;
+
+/// Controls whether (and how) an existing target is backed up before being
+/// removed. Mirrors the GNU `--backup[=CONTROL]` family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackupMode {
+    #[default]
+    None,
+    /// Always append `suffix` to the victim's name.
+    Simple,
+    /// Always rename to `name.~N~`, picking the next unused N.
+    Numbered,
+    /// Numbered if a numbered backup already exists for this target,
+    /// otherwise simple.
+    Existing,
+}
+
+impl BackupMode {
+    pub fn parse(control: &str) -> Result<BackupMode> {
+        match control {
+            "none" | "off" => Ok(BackupMode::None),
+            "simple" | "never" => Ok(BackupMode::Simple),
+            "numbered" | "t" => Ok(BackupMode::Numbered),
+            "existing" | "nil" => Ok(BackupMode::Existing),
+            other => Err(anyhow!("invalid backup control {other}")),
+        }
+    }
+}
+
+fn numbered_prefix(target: &Path) -> Result<(PathBuf, String)> {
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", target.display()))?
+        .to_string_lossy()
+        .to_string();
+    let parent = target.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
+    Ok((parent, format!("{file_name}.~")))
+}
+
+// Enumerate the parent directory once and collect every existing `N` for
+// `name.~N~` siblings of `target`. Goes through `Fs` (rather than
+// `std::fs::read_dir` directly) so this is exercisable against `FakeFs`
+// without touching the real tree.
+fn existing_numbers(fs: &impl Fs, target: &Path) -> Result<Vec<u64>> {
+    let (parent, prefix) = numbered_prefix(target)?;
+    if !fs.metadata(&parent)?.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut numbers = Vec::new();
+    for entry in fs.read_dir(&parent)? {
+        let name = entry.file_name().unwrap().to_string_lossy().to_string();
+        if let Some(n) = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix('~'))
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            numbers.push(n);
+        }
+    }
+    Ok(numbers)
+}
+
+fn next_numbered_backup(fs: &impl Fs, target: &Path) -> Result<PathBuf> {
+    let (parent, prefix) = numbered_prefix(target)?;
+    let next = existing_numbers(fs, target)?.into_iter().max().unwrap_or(0) + 1;
+    Ok(parent.join(format!("{prefix}{next}~")))
+}
+
+fn simple_backup(target: &Path, suffix: &str) -> PathBuf {
+    let mut name = target.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Compute the backup destination for `target`, if `mode` calls for one.
+/// Always returns a path on the same filesystem as `target` so callers can
+/// move the victim there with a plain rename. Takes `fs` so numbered/existing
+/// scans run against whatever `Fs` the caller is using (real or fake).
+pub fn backup_path_for(
+    fs: &impl Fs,
+    target: &Path,
+    mode: BackupMode,
+    suffix: &str,
+) -> Result<Option<PathBuf>> {
+    match mode {
+        BackupMode::None => Ok(None),
+        BackupMode::Simple => Ok(Some(simple_backup(target, suffix))),
+        BackupMode::Numbered => Ok(Some(next_numbered_backup(fs, target)?)),
+        BackupMode::Existing => {
+            if existing_numbers(fs, target)?.is_empty() {
+                Ok(Some(simple_backup(target, suffix)))
+            } else {
+                Ok(Some(next_numbered_backup(fs, target)?))
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+
+use crate::fs::{Fs, Metadata};
+
+#[derive(Debug, Clone)]
+enum Node {
+    File,
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// An in-memory tree standing in for a filesystem, so `Stor`'s stow/unstow
+/// logic can be exercised deterministically without touching `$HOME`.
+pub struct FakeFs {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+}
+
+impl FakeFs {
+    pub fn new() -> Self {
+        Self {
+            nodes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with_file(self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        self.ensure_parents(&path);
+        self.nodes.borrow_mut().insert(path, Node::File);
+        self
+    }
+
+    pub fn with_dir(self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        self.ensure_parents(&path);
+        self.nodes.borrow_mut().entry(path).or_insert(Node::Dir);
+        self
+    }
+
+    pub fn with_symlink(self, link: impl Into<PathBuf>, original: impl Into<PathBuf>) -> Self {
+        let link = link.into();
+        self.ensure_parents(&link);
+        self.nodes
+            .borrow_mut()
+            .insert(link, Node::Symlink(original.into()));
+        self
+    }
+
+    fn ensure_parents(&self, path: &Path) {
+        let mut ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            if ancestor.as_os_str().is_empty() {
+                continue;
+            }
+            self.nodes
+                .borrow_mut()
+                .entry(ancestor.to_path_buf())
+                .or_insert(Node::Dir);
+        }
+    }
+}
+
+impl Default for FakeFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fs for FakeFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .nodes
+            .borrow()
+            .keys()
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        Ok(match self.nodes.borrow().get(path) {
+            Some(Node::File) => Metadata::File,
+            Some(Node::Dir) => Metadata::Dir,
+            Some(Node::Symlink(target)) => Metadata::Symlink(target.clone()),
+            None => Metadata::Missing,
+        })
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        self.ensure_parents(link);
+        self.nodes
+            .borrow_mut()
+            .insert(link.to_path_buf(), Node::Symlink(original.to_path_buf()));
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        match self.nodes.borrow().get(path) {
+            Some(Node::Symlink(target)) => Ok(target.clone()),
+            _ => Err(anyhow!("{} is not a symlink", path.display())),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let mut nodes = self.nodes.borrow_mut();
+        let doomed: Vec<PathBuf> = nodes
+            .keys()
+            .filter(|p| *p == path || p.starts_with(path))
+            .cloned()
+            .collect();
+        for p in doomed {
+            nodes.remove(&p);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut nodes = self.nodes.borrow_mut();
+        let moved: Vec<PathBuf> = nodes
+            .keys()
+            .filter(|p| *p == from || p.starts_with(from))
+            .cloned()
+            .collect();
+        for p in moved {
+            if let Some(node) = nodes.remove(&p) {
+                let rest = p.strip_prefix(from).unwrap();
+                nodes.insert(to.join(rest), node);
+            }
+        }
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.ensure_parents(path);
+        self.nodes
+            .borrow_mut()
+            .entry(path.to_path_buf())
+            .or_insert(Node::Dir);
+        Ok(())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        match self.nodes.borrow().get(from) {
+            Some(Node::File) => {}
+            _ => return Err(anyhow!("{} is not a file", from.display())),
+        }
+        self.ensure_parents(to);
+        self.nodes.borrow_mut().insert(to.to_path_buf(), Node::File);
+        Ok(())
+    }
+
+    fn copy_dir(&self, from: &Path, to: &Path) -> Result<()> {
+        let entries: Vec<(PathBuf, Node)> = self
+            .nodes
+            .borrow()
+            .iter()
+            .filter(|(p, _)| p.starts_with(from))
+            .map(|(p, n)| (p.clone(), n.clone()))
+            .collect();
+        for (p, node) in entries {
+            let rest = p.strip_prefix(from).unwrap();
+            self.nodes.borrow_mut().insert(to.join(rest), node);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+
+use crate::reflink::{self, ReflinkMode};
+
+/// What's at a path, without erroring when there's nothing there — callers
+/// in this crate check existence and kind constantly, so `Missing` is a
+/// normal result rather than an `Err`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Metadata {
+    File,
+    Dir,
+    Symlink(PathBuf),
+    Missing,
+}
+
+impl Metadata {
+    pub fn exists(&self) -> bool {
+        !matches!(self, Metadata::Missing)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        matches!(self, Metadata::Symlink(_))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Metadata::Dir)
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self, Metadata::File)
+    }
+}
+
+/// Every filesystem operation `Stor` needs, so the stow/unstow/restow logic
+/// can run against a real filesystem or an in-memory fake.
+pub trait Fs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+    fn metadata(&self, path: &Path) -> Result<Metadata>;
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()>;
+    fn read_link(&self, path: &Path) -> Result<PathBuf>;
+    fn remove(&self, path: &Path) -> Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()>;
+    fn copy_dir(&self, from: &Path, to: &Path) -> Result<()>;
+}
+
+/// The real filesystem, via `std`/`fs_extra`. The reflink mode used for
+/// `--copy` is baked in at construction time.
+pub struct RealFs {
+    reflink_mode: ReflinkMode,
+}
+
+impl RealFs {
+    pub fn new(reflink_mode: ReflinkMode) -> Self {
+        Self { reflink_mode }
+    }
+}
+
+impl Fs for RealFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            entries.push(entry?.path());
+        }
+        Ok(entries)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        if path.is_symlink() {
+            return Ok(Metadata::Symlink(std::fs::read_link(path)?));
+        }
+        if path.is_dir() {
+            return Ok(Metadata::Dir);
+        }
+        if path.is_file() {
+            return Ok(Metadata::File);
+        }
+        Ok(Metadata::Missing)
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        std::os::unix::fs::symlink(original, link).map_err(|err| anyhow!(err))
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        Ok(std::fs::read_link(path)?)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        fs_extra::remove_items(&[path])?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        if reflink::reflink_file(from, to, self.reflink_mode)? {
+            return Ok(());
+        }
+        let options = fs_extra::file::CopyOptions::default();
+        fs_extra::file::copy(from, to, &options)?;
+        Ok(())
+    }
+
+    // Recreate the directory structure under `to` and copy (or reflink) each
+    // file it contains, rather than relying on `fs_extra::dir::copy`, so the
+    // destination name doesn't have to match the source's.
+    fn copy_dir(&self, from: &Path, to: &Path) -> Result<()> {
+        self.create_dir_all(to)?;
+        for entry in self.read_dir(from)? {
+            let dst = to.join(entry.file_name().unwrap());
+            if entry.is_dir() {
+                self.copy_dir(&entry, &dst)?;
+            } else if entry.is_file() {
+                self.copy_file(&entry, &dst)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashSet;
+
+/// Resolves host-specific dotfile entries, e.g. `config@@mylaptop`, against
+/// the machine's hostname so the same package directory can be stowed
+/// unmodified on several machines.
+pub struct HostFilter {
+    sep: String,
+    hostname: String,
+}
+
+impl HostFilter {
+    pub fn new(sep: String) -> Self {
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        Self { sep, hostname }
+    }
+
+    // Split "name<sep>host" into ("name", "host"); None if `name` carries no
+    // host marker at all.
+    fn split_host<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str)> {
+        if self.sep.is_empty() {
+            return None;
+        }
+        name.rsplit_once(self.sep.as_str())
+    }
+
+    /// True if this entry should be considered on the current host: either
+    /// it carries no host marker, or the marker matches our hostname.
+    pub fn matches(&self, name: &str) -> bool {
+        match self.split_host(name) {
+            Some((_, host)) => host == self.hostname,
+            None => true,
+        }
+    }
+
+    /// Strip a matching `<sep><hostname>` suffix so `config@@mylaptop`
+    /// resolves to the same target as `config`. Names for other hosts, or
+    /// without a marker, are returned unchanged.
+    pub fn strip(&self, name: &str) -> String {
+        match self.split_host(name) {
+            Some((base, host)) if host == self.hostname => base.to_string(),
+            _ => name.to_string(),
+        }
+    }
+
+    /// Scan a directory's raw entry names and collect the base names that
+    /// have a host-specific variant for *this* host, so the generic entry
+    /// of the same name can be suppressed in favor of it.
+    pub fn host_specific_bases<I, S>(&self, names: I) -> HashSet<String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        names
+            .into_iter()
+            .filter_map(|name| match self.split_host(name.as_ref()) {
+                Some((base, host)) if host == self.hostname => Some(base.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -1,7 +1,5 @@
 use std::{
-    fs::read_dir,
     io::Write,
-    os::unix::fs::symlink,
     path::{Path, PathBuf},
 };
 
@@ -10,6 +8,22 @@ use clap::Parser;
 use colored::Colorize;
 use log::{error, info, warn};
 
+mod backup;
+#[cfg(test)]
+mod fake_fs;
+mod fs;
+mod host;
+mod plan;
+mod reflink;
+mod rename;
+
+use backup::BackupMode;
+use fs::{Fs, Metadata, RealFs};
+use host::HostFilter;
+use plan::Action;
+use reflink::ReflinkMode;
+use rename::RenameRules;
+
 #[derive(Parser, Default)]
 struct Args {
     #[arg(
@@ -54,27 +68,113 @@ struct Args {
         help = "Delete if files/symlinks already exists"
     )]
     overwrite: bool,
+    #[arg(
+        long = "backup",
+        num_args = 0..=1,
+        default_missing_value = "existing",
+        value_name = "CONTROL",
+        help = "Back up existing files before overwriting them (none, simple, numbered, existing)"
+    )]
+    backup: Option<String>,
+    #[arg(
+        long = "suffix",
+        default_value = "~",
+        help = "Backup suffix used in simple backup mode"
+    )]
+    suffix: String,
+    #[arg(
+        long = "host-sep",
+        default_value = "@@",
+        value_name = "SEP",
+        help = "Separator marking host-specific entries, e.g. config@@myhost"
+    )]
+    host_sep: String,
+    #[arg(
+        long = "dotfiles",
+        default_value_t = false,
+        help = "Rewrite dot-foo path components to .foo when computing targets"
+    )]
+    dotfiles: bool,
+    #[arg(
+        long = "reflink",
+        num_args = 0..=1,
+        default_missing_value = "always",
+        value_name = "WHEN",
+        help = "With --copy, clone files copy-on-write instead of copying their data (auto, always, never)"
+    )]
+    reflink: Option<String>,
     modules: Vec<String>,
 }
 
-struct Stor {
+struct Stor<F: Fs> {
     args: Args,
+    backup_mode: BackupMode,
+    host: HostFilter,
+    rename_rules: RenameRules,
+    fs: F,
 }
 
-impl Stor {
-    fn new(mut args: Args) -> Stor {
+impl Stor<RealFs> {
+    fn new(args: Args) -> Result<Stor<RealFs>> {
+        let reflink_mode = match &args.reflink {
+            Some(when) => ReflinkMode::parse(when)?,
+            None => ReflinkMode::Never,
+        };
+        Stor::with_fs(args, RealFs::new(reflink_mode))
+    }
+}
+
+impl<F: Fs> Stor<F> {
+    fn with_fs(mut args: Args, fs: F) -> Result<Stor<F>> {
         // handle default values
         if args.targetdir.is_none() {
             args.targetdir = Some(home::home_dir().unwrap().to_str().unwrap().to_string());
         }
-        Self { args }
+        let backup_mode = match &args.backup {
+            Some(control) => BackupMode::parse(control)?,
+            None => BackupMode::None,
+        };
+        let host = HostFilter::new(args.host_sep.clone());
+        let mut rename_rules = RenameRules::new();
+        if args.dotfiles {
+            rename_rules.push(RenameRules::dotfiles_rule()?);
+        }
+        Ok(Self {
+            args,
+            backup_mode,
+            host,
+            rename_rules,
+            fs,
+        })
+    }
+
+    // Move an about-to-be-deleted target out of the way if a backup mode is
+    // configured, so it never gets thrown away unrecoverably. Returns true
+    // if the target has (or, in simulate mode, would have) already been
+    // relocated, meaning callers must not also remove it.
+    fn backup_target(&self, target: &Path) -> Result<bool> {
+        let Some(backup_path) =
+            backup::backup_path_for(&self.fs, target, self.backup_mode, &self.args.suffix)?
+        else {
+            return Ok(false);
+        };
+        info!(
+            "{}",
+            format!("Backup: {} -> {}", target.display(), backup_path.display()).cyan()
+        );
+        if !self.args.simulate {
+            self.fs.rename(target, &backup_path)?;
+        }
+        Ok(true)
     }
 
     fn run(self) -> Result<()> {
+        let mut module_targets = Vec::new();
+        let mut plan = Vec::new();
         for module in &self.args.modules {
             // check input
             let module = module.parse::<PathBuf>().unwrap();
-            if !module.is_dir() {
+            if !self.fs.metadata(&module)?.is_dir() {
                 warn!(
                     "{}",
                     format!(
@@ -92,7 +192,7 @@ impl Stor {
                 .unwrap()
                 .parse::<PathBuf>()
                 .unwrap();
-            if !target.is_dir() {
+            if !self.fs.metadata(&target)?.is_dir() {
                 warn!(
                     "{}",
                     format!(
@@ -108,15 +208,44 @@ impl Stor {
             let module = std::path::absolute(module).unwrap();
             let target = std::path::absolute(target).unwrap();
 
+            // build the plan for this module before touching anything, so a
+            // conflict anywhere aborts the whole run rather than leaving a
+            // partially-applied tree
+            if !self.args.delete {
+                self.plan_stow(&module, &target, &module, &mut plan)?;
+            }
+            module_targets.push((module, target));
+        }
+
+        if !self.args.delete {
+            let conflicts = plan.iter().filter(|action| action.is_conflict()).count();
+            if self.args.simulate {
+                plan::report(&plan);
+            }
+            if conflicts > 0 && !self.args.overwrite {
+                if !self.args.simulate {
+                    plan::report(&plan);
+                }
+                return Err(anyhow!(
+                    "{conflicts} conflict(s) found; rerun with --overwrite to proceed"
+                ));
+            }
+            if self.args.simulate {
+                // the plan above is the complete report; nothing left to do
+                return Ok(());
+            }
+        }
+
+        for (module, target) in &module_targets {
             // run commands
             if self.args.delete {
                 // delete links and files
-                self.unstow(&module, &target, &module)?;
+                self.unstow(module, target, module)?;
             } else if self.args.restow {
-                self.restow(&module, &target, &module)?;
+                self.restow(module, target, module)?;
             } else {
                 // create links and files
-                self.stow(&module, &target, &module)?;
+                self.stow(module, target, module)?;
             }
         }
 
@@ -130,55 +259,141 @@ impl Stor {
     }
 
     fn copy_or_link(&self, path: &Path, target: &Path) -> Result<()> {
+        let meta = self.fs.metadata(path)?;
         if self.args.copy {
             // copy is enabled.
             info!(
                 "{}",
                 format!("Copy: {} -> {}", path.display(), target.display()).cyan()
             );
-            if path.is_dir() {
-                if !self.args.simulate {
-                    let options = fs_extra::dir::CopyOptions::default();
-                    fs_extra::dir::copy(path, target.parent().unwrap(), &options)?;
-                }
-            } else if path.is_file() {
-                #[allow(clippy::collapsible_if)]
-                if !self.args.simulate {
-                    let options = fs_extra::file::CopyOptions::default();
-                    fs_extra::file::copy(path, target, &options)?;
+            if !self.args.simulate {
+                if meta.is_dir() {
+                    self.fs.copy_dir(path, target)?;
+                } else if meta.is_file() {
+                    self.fs.copy_file(path, target)?;
                 }
             }
+        } else if meta.is_dir() && self.dir_needs_expansion(path)? {
+            // folding this dir into one symlink would hide a rename or
+            // host-suppression that applies somewhere inside it, so build
+            // the real directory here and recurse instead.
+            info!(
+                "{}",
+                format!(
+                    "Link: {} -> {} (expanded, contents are renamed)",
+                    path.display(),
+                    target.display()
+                )
+                .cyan()
+            );
+            if !self.args.simulate {
+                self.expand_dir(path, target)?;
+            }
         } else {
             // copy is diabled, use default symlink.
             info!(
                 "{}",
                 format!("Link: {} -> {}", path.display(), target.display()).cyan()
             );
-            if !self.args.simulate {
-                if path.is_dir() {
-                    symlink(path, target).map_err(|err| anyhow!(err))?;
-                } else if path.is_file() {
-                    symlink(path, target).map_err(|err| anyhow!(err))?;
-                }
+            if !self.args.simulate && (meta.is_dir() || meta.is_file()) {
+                self.fs.symlink(path, target)?;
             }
         }
         Ok(())
     }
 
+    // True if folding `dir` into a single directory symlink would expose an
+    // entry (anywhere in its subtree) under the wrong name: one `relative_target`
+    // would rename, or one a host filter would suppress or hide.
+    fn dir_needs_expansion(&self, dir: &Path) -> Result<bool> {
+        let entries = self.fs.read_dir(dir)?;
+        let names: Vec<String> = entries
+            .iter()
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        let host_specific = self.host.host_specific_bases(&names);
+
+        for child in &entries {
+            let name = child.file_name().unwrap().to_string_lossy().to_string();
+            if !self.host.matches(&name) || host_specific.contains(&name) {
+                return Ok(true);
+            }
+            if self.rename_rules.apply(&self.host.strip(&name)) != name {
+                return Ok(true);
+            }
+            if self.fs.metadata(child)?.is_dir() && self.dir_needs_expansion(child)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Build a real directory at `target` mirroring `path`, renaming each
+    // child the same way `relative_target` would, so a `--dotfiles`/host-sep
+    // rewrite that applies somewhere inside isn't hidden behind a single
+    // folded directory symlink.
+    fn expand_dir(&self, path: &Path, target: &Path) -> Result<()> {
+        self.fs.create_dir_all(target)?;
+        let entries = self.fs.read_dir(path)?;
+        let names: Vec<String> = entries
+            .iter()
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        let host_specific = self.host.host_specific_bases(&names);
+
+        for child in entries {
+            let name = child.file_name().unwrap().to_string_lossy().to_string();
+            if !self.host.matches(&name) || host_specific.contains(&name) {
+                continue;
+            }
+            let linked_name = self.rename_rules.apply(&self.host.strip(&name));
+            self.copy_or_link(&child, &target.join(linked_name))?;
+        }
+        Ok(())
+    }
+
     fn stow(&self, module: &Path, targetdir: &Path, current: &Path) -> Result<()> {
-        for entry in read_dir(current)? {
-            let entry = entry?;
-            let path = entry.path();
-            let target = get_relative_target(path.as_path(), module, targetdir);
+        // Pre-scan so a host-specific entry (e.g. `config@@mylaptop`) can
+        // suppress its generic sibling (`config`) for this host.
+        let entries = self.fs.read_dir(current)?;
+        let names: Vec<String> = entries
+            .iter()
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        let host_specific = self.host.host_specific_bases(&names);
+
+        for path in entries {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+            // entries tagged for a different host are not ours to stow
+            if !self.host.matches(&name) {
+                continue;
+            }
+            // a host-specific variant for this host exists, so the generic
+            // entry of the same name must yield to it
+            if host_specific.contains(&name) {
+                info!(
+                    "{}",
+                    format!(
+                        "Skip: {} has a host-specific variant for this host",
+                        path.display()
+                    )
+                    .cyan()
+                );
+                continue;
+            }
+
+            let target = self.relative_target(path.as_path(), module, targetdir);
+            let target_meta = self.fs.metadata(&target)?;
 
             // if target is a symlink
-            if target.is_symlink() {
+            if target_meta.is_symlink() {
                 // if the copy flag is on, then try to overwrite, give up if overwrite flag is off
                 if self.args.copy {
                     if self.args.overwrite {
                         info!("{}", format!("Unlink: {}", target.display()).cyan());
-                        if !self.args.simulate {
-                            fs_extra::remove_items(&[&target])?;
+                        if !self.backup_target(&target)? && !self.args.simulate {
+                            self.fs.remove(&target)?;
                         }
                     } else {
                         warn!(
@@ -189,19 +404,25 @@ impl Stor {
                     }
                 } else {
                     // if the symlink already points to the target path, then skip, otherwise try overwrite.
-                    let origin = std::fs::read_link(&target)?;
+                    let origin = self.fs.read_link(&target)?;
                     if origin == path {
                         info!(
                             "{}",
                             format!("Skip: {} already exists", target.display()).cyan()
                         );
                         continue;
+                    } else if self.fs.metadata(&path)?.is_dir() && self.fs.metadata(&origin)?.is_dir() {
+                        // a directory belonging to another package is folded
+                        // into one symlink here; unfold it into real
+                        // per-file links instead of destroying it, then fall
+                        // through to stow this package's children into it.
+                        self.unfold(&target, &origin)?;
                     } else {
                         #[allow(clippy::collapsible_else_if)]
                         if self.args.overwrite {
                             info!("{}", format!("Delete: {}", target.display()).cyan());
-                            if !self.args.simulate {
-                                fs_extra::remove_items(&[&target])?;
+                            if !self.backup_target(&target)? && !self.args.simulate {
+                                self.fs.remove(&target)?;
                             }
                         } else {
                             warn!(
@@ -215,11 +436,13 @@ impl Stor {
             }
 
             // try overwrite or skip if there's any target already exists
-            if path.is_file() && target.exists() {
+            let path_meta = self.fs.metadata(&path)?;
+            let target_meta = self.fs.metadata(&target)?;
+            if path_meta.is_file() && target_meta.exists() {
                 if self.args.overwrite {
                     warn!("{}", format!("Delete: {}", target.display()).yellow());
-                    if !self.args.simulate {
-                        fs_extra::remove_items(&[&target])?;
+                    if !self.backup_target(&target)? && !self.args.simulate {
+                        self.fs.remove(&target)?;
                     }
                 } else {
                     warn!(
@@ -231,63 +454,319 @@ impl Stor {
             }
 
             // if target not exists, copy or link path to it.
-            if !target.exists() {
+            let target_meta = self.fs.metadata(&target)?;
+            if !target_meta.exists() {
                 self.copy_or_link(&path, &target)?;
                 continue;
             }
 
             // if target is a dir, then repeat.
-            if target.is_dir() {
+            if target_meta.is_dir() {
                 self.stow(module, targetdir, &path)?;
             }
         }
         Ok(())
     }
 
+    // Read-only mirror of `stow`, producing the `Action`s it would perform
+    // instead of performing them, so `run` can check for conflicts across
+    // every module up front before any of them touch the filesystem.
+    fn plan_stow(
+        &self,
+        module: &Path,
+        targetdir: &Path,
+        current: &Path,
+        actions: &mut Vec<Action>,
+    ) -> Result<()> {
+        let entries = self.fs.read_dir(current)?;
+        let names: Vec<String> = entries
+            .iter()
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        let host_specific = self.host.host_specific_bases(&names);
+
+        for path in entries {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+            if !self.host.matches(&name) {
+                continue;
+            }
+            if host_specific.contains(&name) {
+                actions.push(Action::Skip {
+                    target: path.clone(),
+                    reason: "has a host-specific variant for this host".to_string(),
+                });
+                continue;
+            }
+
+            let target = self.relative_target(path.as_path(), module, targetdir);
+            let target_meta = self.fs.metadata(&target)?;
+            // set once we've planned to clear whatever's at `target`, so the
+            // generic "does a target already exist" checks below see it as
+            // gone without us actually having removed it
+            let mut cleared = false;
+
+            if target_meta.is_symlink() {
+                if self.args.copy {
+                    if self.args.overwrite {
+                        self.plan_clear(&target, actions)?;
+                        cleared = true;
+                    } else {
+                        actions.push(Action::Conflict {
+                            target: target.clone(),
+                            reason: "a symlink exists where a copy is expected".to_string(),
+                        });
+                        continue;
+                    }
+                } else {
+                    let origin = self.fs.read_link(&target)?;
+                    if origin == path {
+                        actions.push(Action::Skip {
+                            target: target.clone(),
+                            reason: "already exists".to_string(),
+                        });
+                        continue;
+                    } else if self.fs.metadata(&path)?.is_dir() && self.fs.metadata(&origin)?.is_dir()
+                    {
+                        // would be unfolded and merged into, not a conflict
+                        actions.push(Action::Unfold {
+                            target: target.clone(),
+                            origin: origin.clone(),
+                        });
+                        self.plan_stow(module, targetdir, &path, actions)?;
+                        continue;
+                    } else if self.args.overwrite {
+                        self.plan_clear(&target, actions)?;
+                        cleared = true;
+                    } else {
+                        actions.push(Action::Conflict {
+                            target: target.clone(),
+                            reason: format!("already linked to {}", origin.display()),
+                        });
+                        continue;
+                    }
+                }
+            }
+
+            let path_meta = self.fs.metadata(&path)?;
+            if !cleared && path_meta.is_file() && target_meta.exists() {
+                if self.args.overwrite {
+                    self.plan_clear(&target, actions)?;
+                    cleared = true;
+                } else {
+                    actions.push(Action::Conflict {
+                        target: target.clone(),
+                        reason: "already exists".to_string(),
+                    });
+                    continue;
+                }
+            }
+
+            if cleared || !target_meta.exists() {
+                if self.args.copy {
+                    actions.push(Action::Copy {
+                        source: path.clone(),
+                        target: target.clone(),
+                    });
+                } else {
+                    actions.push(Action::Link {
+                        source: path.clone(),
+                        target: target.clone(),
+                    });
+                }
+                continue;
+            }
+
+            // if target is a dir, then repeat.
+            if target_meta.is_dir() {
+                self.plan_stow(module, targetdir, &path, actions)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Record what `backup_target` would do to `target` without touching the
+    // filesystem: a backup rename if a backup mode is set, otherwise a plain
+    // delete (the two are mutually exclusive, same as `backup_target`).
+    fn plan_clear(&self, target: &Path, actions: &mut Vec<Action>) -> Result<()> {
+        match backup::backup_path_for(&self.fs, target, self.backup_mode, &self.args.suffix)? {
+            Some(backup_path) => actions.push(Action::Backup {
+                target: target.to_path_buf(),
+                backup_path,
+            }),
+            None => actions.push(Action::Delete {
+                target: target.to_path_buf(),
+            }),
+        }
+        Ok(())
+    }
+
     fn unstow(&self, module: &Path, targetdir: &Path, current: &Path) -> Result<()> {
-        for entry in read_dir(current)? {
-            let entry = entry?;
-            let path = entry.path();
-            let target = get_relative_target(path.as_path(), module, targetdir);
-
-            // if target exists, remove it
-            if target.is_symlink() {
-                info!("{}", format!("Unlink: {}", target.display()).cyan());
-                if !self.args.simulate {
-                    fs_extra::remove_items(&[&target])?;
+        for path in self.fs.read_dir(current)? {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+            // mirror stow's host filtering: entries for a different host
+            // were never linked, so there's nothing to unstow here
+            if !self.host.matches(&name) {
+                continue;
+            }
+
+            let target = self.relative_target(path.as_path(), module, targetdir);
+            let target_meta = self.fs.metadata(&target)?;
+
+            // if target exists, remove it. A symlink is removed only if it
+            // points back at this entry (one pointing elsewhere is another
+            // package's file, or a folded directory another package owns);
+            // a plain file is removed without any ownership check.
+            if target_meta.is_symlink() {
+                if self.fs.read_link(&target)? == path {
+                    info!("{}", format!("Unlink: {}", target.display()).cyan());
+                    if !self.args.simulate {
+                        self.fs.remove(&target)?;
+                    }
+                } else {
+                    warn!(
+                        "{}",
+                        format!("Skip: {} is owned by another package", target.display()).yellow()
+                    );
                 }
-            } else if target.is_file() {
+            } else if target_meta.is_file() {
                 info!("{}", format!("Delete: {}", target.display()).cyan());
                 if !self.args.simulate {
-                    fs_extra::remove_items(&[&target])?;
+                    self.fs.remove(&target)?;
                 }
                 // father is empty
-                if target.parent().unwrap().read_dir()?.next().is_none() {
+                let parent = target.parent().unwrap();
+                if self.fs.read_dir(parent)?.is_empty() {
                     #[allow(clippy::collapsible_if)]
                     if !self.args.simulate {
-                        fs_extra::remove_items(&[&target.parent().unwrap()])?;
+                        self.fs.remove(parent)?;
                     }
                 }
             }
             // if target is a dir, then repeat.
-            else if target.is_dir() {
+            else if target_meta.is_dir() {
                 self.unstow(module, targetdir, &path)?;
+                // once this package's entries are gone, the directory might
+                // now be owned by a single remaining package, in which case
+                // it can collapse back into one directory symlink.
+                self.try_fold(&target)?;
             }
         }
         Ok(())
     }
 
+    // A target that's a symlink into *another* package's directory is a
+    // fold: exactly one package owns it. Replace it with a real directory
+    // holding one symlink per child of that package's directory, so a
+    // second package can contribute files alongside it.
+    fn unfold(&self, target: &Path, origin_dir: &Path) -> Result<()> {
+        info!(
+            "{}",
+            format!("Unfold: {} ({})", target.display(), origin_dir.display()).cyan()
+        );
+        if self.args.simulate {
+            return Ok(());
+        }
+        self.fs.remove(target)?;
+        self.fs.create_dir_all(target)?;
+
+        let children = self.fs.read_dir(origin_dir)?;
+        let names: Vec<String> = children
+            .iter()
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        let host_specific = self.host.host_specific_bases(&names);
+
+        for child in children {
+            let name = child.file_name().unwrap().to_string_lossy().to_string();
+            // mirror stow's own host filtering: a child tagged for another
+            // host, or shadowed by a host-specific sibling, was never
+            // supposed to be visible under its raw name
+            if !self.host.matches(&name) || host_specific.contains(&name) {
+                continue;
+            }
+            let linked_name = self.rename_rules.apply(&self.host.strip(&name));
+            self.fs.symlink(&child, &target.join(linked_name))?;
+        }
+        Ok(())
+    }
+
+    // Inverse of unfold: if every child of `target` is a symlink into the
+    // same package directory, collapse it back into a single directory
+    // symlink.
+    fn try_fold(&self, target: &Path) -> Result<()> {
+        let children = self.fs.read_dir(target)?;
+        if children.is_empty() {
+            return Ok(());
+        }
+        let mut owner: Option<PathBuf> = None;
+        let mut linked_names = Vec::new();
+        for child in &children {
+            let Metadata::Symlink(origin) = self.fs.metadata(child)? else {
+                return Ok(());
+            };
+            let Some(origin_dir) = origin.parent().map(Path::to_path_buf) else {
+                return Ok(());
+            };
+            match &owner {
+                None => owner = Some(origin_dir),
+                Some(existing) if *existing != origin_dir => return Ok(()),
+                _ => {}
+            }
+            linked_names.push(child.file_name().unwrap().to_os_string());
+        }
+        let Some(origin_dir) = owner else {
+            return Ok(());
+        };
+
+        // every child here links into origin_dir, but the reverse must hold
+        // too: if origin_dir has entries not linked here (excluded by the
+        // host filter, or a partial stow), folding would expose them even
+        // though some package deliberately left them out.
+        let mut origin_names: Vec<_> = self
+            .fs
+            .read_dir(&origin_dir)?
+            .iter()
+            .filter_map(|p| p.file_name().map(|n| n.to_os_string()))
+            .collect();
+        linked_names.sort_unstable();
+        origin_names.sort_unstable();
+        if linked_names != origin_names {
+            return Ok(());
+        }
+
+        info!(
+            "{}",
+            format!("Fold: {} -> {}", target.display(), origin_dir.display()).cyan()
+        );
+        if self.args.simulate {
+            return Ok(());
+        }
+        self.fs.remove(target)?;
+        self.fs.symlink(&origin_dir, target)?;
+        Ok(())
+    }
+
     fn restow(&self, module: &Path, targetdir: &Path, current: &Path) -> Result<()> {
         self.unstow(module, targetdir, current)?;
         self.stow(module, targetdir, current)?;
         Ok(())
     }
-}
 
-// calculate the target path based on src(file/dir path), root(dotfile dir path) and dst(target dir path)
-fn get_relative_target(src: &Path, root: &Path, dst: &Path) -> PathBuf {
-    let relative_path = src.strip_prefix(root).unwrap();
-    dst.join(relative_path)
+    // calculate the target path based on src(file/dir path), root(dotfile dir path) and dst(target dir path):
+    // strip any matching host-specific marker, then apply the renaming
+    // rules (e.g. --dotfiles) to every component of the relative path.
+    fn relative_target(&self, src: &Path, root: &Path, dst: &Path) -> PathBuf {
+        let relative_path = src.strip_prefix(root).unwrap();
+        let mut target = dst.to_path_buf();
+        for component in relative_path.components() {
+            let name = component.as_os_str().to_string_lossy();
+            let name = self.host.strip(&name);
+            target.push(self.rename_rules.apply(&name));
+        }
+        target
+    }
 }
 
 fn main() {
@@ -303,8 +782,362 @@ fn main() {
         })
         .init();
     let args = Args::parse();
-    let stor = Stor::new(args);
+    let stor = match Stor::new(args) {
+        Ok(stor) => stor,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
     if let Err(err) = stor.run() {
         error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_fs::FakeFs;
+
+    fn stor(args: Args, fs: FakeFs) -> Stor<FakeFs> {
+        Stor::with_fs(args, fs).unwrap()
+    }
+
+    #[test]
+    fn stow_links_a_plain_file() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home");
+        let stor = stor(Args::default(), fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/module/pkg/file"))
+        );
+    }
+
+    #[test]
+    fn stow_skips_a_correct_existing_link() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_symlink("/home/file", "/module/pkg/file");
+        let stor = stor(Args::default(), fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/module/pkg/file"))
+        );
+    }
+
+    #[test]
+    fn stow_refuses_to_overwrite_without_the_flag() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_file("/home/file");
+        let stor = stor(Args::default(), fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        // the pre-existing plain file must survive untouched
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::File
+        );
+    }
+
+    #[test]
+    fn stow_overwrite_backs_up_the_conflicting_file() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_file("/home/file");
+        let args = Args {
+            overwrite: true,
+            backup: Some("simple".to_string()),
+            suffix: "~".to_string(),
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file~")).unwrap(),
+            fs::Metadata::File
+        );
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/module/pkg/file"))
+        );
+    }
+
+    #[test]
+    fn simulate_mode_makes_no_changes() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home");
+        let args = Args {
+            simulate: true,
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::Missing
+        );
+    }
+
+    #[test]
+    fn unstow_removes_a_link_it_owns() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_symlink("/home/file", "/module/pkg/file");
+        let stor = stor(Args::default(), fs);
+
+        stor.unstow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::Missing
+        );
+    }
+
+    #[test]
+    fn stow_overwrite_numbered_backup_scans_the_fake_fs() {
+        // the numbered scan must go through `Fs` so it sees `/home/file.~1~`
+        // on the fake tree instead of (wrongly) enumerating the real cwd.
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_file("/home/file")
+            .with_file("/home/file.~1~");
+        let args = Args {
+            overwrite: true,
+            backup: Some("numbered".to_string()),
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file.~2~")).unwrap(),
+            fs::Metadata::File
+        );
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/file")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/module/pkg/file"))
+        );
+    }
+
+    #[test]
+    fn try_fold_refuses_when_the_source_dir_has_unlinked_entries() {
+        // /module/pkg/dir/other was never linked here (e.g. excluded by the
+        // host filter), so folding /home/dir back into a single directory
+        // symlink would wrongly expose it.
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/dir/file")
+            .with_file("/module/pkg/dir/other")
+            .with_dir("/home/dir")
+            .with_symlink("/home/dir/file", "/module/pkg/dir/file");
+        let stor = stor(Args::default(), fs);
+
+        stor.try_fold(Path::new("/home/dir")).unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/dir")).unwrap(),
+            fs::Metadata::Dir
+        );
+    }
+
+    #[test]
+    fn unfold_applies_rename_rules_to_each_relinked_child() {
+        let fs = FakeFs::new()
+            .with_file("/pkgA/dot-config/dot-bashrc")
+            .with_dir("/home")
+            .with_symlink("/home/.config", "/pkgA/dot-config");
+        let args = Args {
+            dotfiles: true,
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        stor.unfold(Path::new("/home/.config"), Path::new("/pkgA/dot-config"))
+            .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/.config/.bashrc")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/pkgA/dot-config/dot-bashrc"))
+        );
+        assert_eq!(
+            stor.fs
+                .metadata(Path::new("/home/.config/dot-bashrc"))
+                .unwrap(),
+            fs::Metadata::Missing
+        );
+    }
+
+    #[test]
+    fn stow_expands_a_folded_dir_when_dotfiles_renames_something_inside() {
+        // folding straight to `.config -> dot-config` would leave
+        // `dot-bashrc` un-renamed inside it; it must be expanded into a real
+        // directory with a renamed per-file link instead.
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/dot-config/dot-bashrc")
+            .with_dir("/home");
+        let args = Args {
+            dotfiles: true,
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/.config")).unwrap(),
+            fs::Metadata::Dir
+        );
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/.config/.bashrc")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/module/pkg/dot-config/dot-bashrc"))
+        );
+    }
+
+    #[test]
+    fn stow_still_folds_a_dir_that_needs_no_renaming() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/config/plain")
+            .with_dir("/home");
+        let stor = stor(Args::default(), fs);
+
+        stor.stow(
+            Path::new("/module/pkg"),
+            Path::new("/home"),
+            Path::new("/module/pkg"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            stor.fs.metadata(Path::new("/home/config")).unwrap(),
+            fs::Metadata::Symlink(PathBuf::from("/module/pkg/config"))
+        );
+    }
+
+    #[test]
+    fn run_aborts_before_touching_anything_when_a_conflict_is_unresolved() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_file("/home/file");
+        let args = Args {
+            targetdir: Some("/home".to_string()),
+            modules: vec!["/module/pkg".to_string()],
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        let err = stor.run().unwrap_err();
+
+        assert!(err.to_string().contains("conflict"));
+    }
+
+    #[test]
+    fn run_resolves_conflicts_and_stows_when_overwrite_is_set() {
+        let fs = FakeFs::new()
+            .with_file("/module/pkg/file")
+            .with_dir("/home")
+            .with_file("/home/file");
+        let args = Args {
+            targetdir: Some("/home".to_string()),
+            modules: vec!["/module/pkg".to_string()],
+            overwrite: true,
+            ..Args::default()
+        };
+        let stor = stor(args, fs);
+
+        stor.run().unwrap();
+    }
+
+    #[test]
+    fn relative_target_strips_host_marker_and_applies_rename_rules() {
+        let hostname = gethostname::gethostname().to_string_lossy().to_string();
+        let args = Args {
+            dotfiles: true,
+            host_sep: "@@".to_string(),
+            ..Args::default()
+        };
+        let stor = stor(args, FakeFs::new());
+
+        let src = PathBuf::from(format!("/module/pkg/dot-config/dot-bashrc@@{hostname}"));
+        let target = stor.relative_target(&src, Path::new("/module/pkg"), Path::new("/home"));
+
+        assert_eq!(target, Path::new("/home/.config/.bashrc"));
+    }
+
+    #[test]
+    fn relative_target_leaves_other_hosts_marker_untouched() {
+        // no host in this repo is named this, so the marker must survive
+        // `strip` unchanged rather than being mistaken for a match.
+        let args = Args {
+            host_sep: "@@".to_string(),
+            ..Args::default()
+        };
+        let stor = stor(args, FakeFs::new());
+
+        let src = Path::new("/module/pkg/config@@some-other-host");
+        let target = stor.relative_target(src, Path::new("/module/pkg"), Path::new("/home"));
+
+        assert_eq!(target, Path::new("/home/config@@some-other-host"));
     }
 }
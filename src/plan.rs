@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use colored::Colorize;
+use log::{info, warn};
+
+/// One intended filesystem change, produced by a planning pass over a
+/// module before any of them actually happen.
+pub enum Action {
+    Link { source: PathBuf, target: PathBuf },
+    Copy { source: PathBuf, target: PathBuf },
+    Backup { target: PathBuf, backup_path: PathBuf },
+    Delete { target: PathBuf },
+    Unfold { target: PathBuf, origin: PathBuf },
+    Skip { target: PathBuf, reason: String },
+    Conflict { target: PathBuf, reason: String },
+}
+
+impl Action {
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Action::Conflict { .. })
+    }
+}
+
+/// Print every action in `plan` as a one-line report, the same way `--simulate`
+/// used to log each change as it happened, but as a single upfront batch.
+pub fn report(plan: &[Action]) {
+    for action in plan {
+        match action {
+            Action::Link { source, target } => info!(
+                "{}",
+                format!("Link: {} -> {}", source.display(), target.display()).cyan()
+            ),
+            Action::Copy { source, target } => info!(
+                "{}",
+                format!("Copy: {} -> {}", source.display(), target.display()).cyan()
+            ),
+            Action::Backup { target, backup_path } => info!(
+                "{}",
+                format!("Backup: {} -> {}", target.display(), backup_path.display()).cyan()
+            ),
+            Action::Delete { target } => {
+                info!("{}", format!("Delete: {}", target.display()).cyan())
+            }
+            Action::Unfold { target, origin } => info!(
+                "{}",
+                format!("Unfold: {} ({})", target.display(), origin.display()).cyan()
+            ),
+            Action::Skip { target, reason } => warn!(
+                "{}",
+                format!("Skip: {} ({reason})", target.display()).yellow()
+            ),
+            Action::Conflict { target, reason } => warn!(
+                "{}",
+                format!("Conflict: {} ({reason})", target.display()).red()
+            ),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use regex::Regex;
+
+/// A single source-pattern -> replacement rule applied to one path
+/// component at a time.
+pub struct RenameRule {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl RenameRule {
+    pub fn new(pattern: &str, replacement: &str) -> Result<Self> {
+        Ok(Self {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    fn apply(&self, component: &str) -> String {
+        self.pattern
+            .replace(component, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// An ordered list of [`RenameRule`]s, applied left-to-right to every
+/// component of a relative path. Used to turn git-friendly, un-hidden
+/// names (`dot-bashrc`) into the real dotfile names (`.bashrc`) stow
+/// actually links.
+#[derive(Default)]
+pub struct RenameRules(Vec<RenameRule>);
+
+impl RenameRules {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, rule: RenameRule) {
+        self.0.push(rule);
+    }
+
+    /// `dot-foo` -> `.foo`, so a repo can keep `dot-config/dot-bashrc`
+    /// visible in git but stow it as `.config/.bashrc`.
+    pub fn dotfiles_rule() -> Result<RenameRule> {
+        RenameRule::new(r"^dot-(.*)$", ".$1")
+    }
+
+    pub fn apply(&self, component: &str) -> String {
+        self.0
+            .iter()
+            .fold(component.to_string(), |acc, rule| rule.apply(&acc))
+    }
+}